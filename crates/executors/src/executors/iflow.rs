@@ -1,8 +1,27 @@
-use std::{path::Path, sync::Arc};
+//! A few pieces of this module would normally live upstream of `IFlow` —
+//! `AvailabilityInfo` growing a version-carrying variant, `backend`-style
+//! config living on a shared ACP executor type, `AcpAgentHarness` owning
+//! session-keyed process handles — but `StandardCodingAgentExecutor`,
+//! `AvailabilityInfo`, and `AcpAgentHarness` are defined outside this crate
+//! slice, so this file can't edit them. `LocalModelBackend`,
+//! `min_supported_version`, `installed_version_info`, and `session_registry`
+//! are the resulting `IFlow`-local stand-ins.
+
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    net::{TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
 use derivative::Derivative;
 use schemars::JsonSchema;
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 use workspace_utils::msg_store::MsgStore;
@@ -17,6 +36,93 @@ use crate::{
     },
 };
 
+/// How long a probed version result is trusted before we shell out again.
+const AVAILABILITY_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Upper bound on how long `probe_installed_version` will block the calling
+/// thread waiting for `iflow --version` before giving up on the probe.
+const VERSION_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keyed by [`IFlow::availability_cache_key`], since the result depends on
+/// config that varies per task (e.g. which local `backend` is configured),
+/// not just on whether iflow is installed at all.
+fn availability_cache() -> &'static Mutex<HashMap<String, (Instant, AvailabilityInfo)>> {
+    static CACHE: OnceLock<Mutex<HashMap<String, (Instant, AvailabilityInfo)>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Caches the last `iflow --version` probe, so `installed_version_info`
+/// (called from `probe_availability` on every availability check) doesn't
+/// shell out again until `AVAILABILITY_CACHE_TTL` has elapsed. Unlike
+/// `availability_cache` this isn't keyed per-backend: the detected version
+/// doesn't depend on which `backend` a task configures.
+fn version_cache() -> &'static Mutex<Option<(Instant, IFlowVersionInfo)>> {
+    static CACHE: OnceLock<Mutex<Option<(Instant, IFlowVersionInfo)>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(None))
+}
+
+/// One harness shared across all spawns, rather than a fresh one per call.
+fn shared_harness() -> &'static AcpAgentHarness {
+    static HARNESS: OnceLock<AcpAgentHarness> = OnceLock::new();
+    HARNESS.get_or_init(AcpAgentHarness::new)
+}
+
+/// `session_id -> (pid, start marker)` for sessions this process has spawned,
+/// keyed on the session id the harness hands back so `is_session_running`/
+/// `cancel_session` can look up the right OS process. The start marker
+/// (`IFlow::process_start_marker`) guards against pid reuse: a pid existing
+/// again isn't enough to call a session "still running" once the OS has had
+/// time to hand that pid to an unrelated process. IFlow-local rather than
+/// living on `AcpAgentHarness` itself.
+fn session_registry() -> &'static Mutex<HashMap<String, (u32, Option<String>)>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<String, (u32, Option<String>)>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Version + minimum-support detail for a probed iflow install. See the
+/// module doc for why this lives here instead of as a field on the shared
+/// `AvailabilityInfo` enum.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IFlowVersionInfo {
+    pub version: Option<String>,
+    pub satisfies_min: bool,
+}
+
+/// Connection details for a locally-hosted, OpenAI-compatible model server,
+/// letting `IFlow` point at a self-hosted endpoint instead of the agent's
+/// default remote, for fully offline use.
+#[derive(Derivative, Clone, Serialize, Deserialize, TS, JsonSchema)]
+#[derivative(Debug, PartialEq)]
+pub struct LocalModelBackend {
+    pub base_url: String,
+    /// Name of the env var (resolved via `ExecutionEnv`) holding the API key
+    /// the local server expects, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_env: Option<String>,
+    pub model: String,
+}
+
+/// A single task-scoped MCP server definition. Mirrors the shape of an entry
+/// in `~/.iflow/settings.json`'s `mcpServers` map, so task configs and the
+/// user's global settings serialize the same way.
+#[derive(Derivative, Clone, Serialize, Deserialize, TS, JsonSchema)]
+#[derivative(Debug, PartialEq)]
+pub struct McpServerConfig {
+    #[serde(default = "McpServerConfig::default_enabled")]
+    pub enabled: bool,
+    pub command: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+}
+
+impl McpServerConfig {
+    fn default_enabled() -> bool {
+        true
+    }
+}
+
 #[derive(Derivative, Clone, Serialize, Deserialize, TS, JsonSchema)]
 #[derivative(Debug, PartialEq)]
 pub struct IFlow {
@@ -26,6 +132,14 @@ pub struct IFlow {
     pub yolo: Option<bool>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<LocalModelBackend>,
+    /// Task-scoped MCP servers, keyed by server name. When non-empty these
+    /// are written to a generated config and take precedence over
+    /// `~/.iflow/settings.json`, so a task can expose its own tools without
+    /// touching the user's global settings.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub mcp_servers: HashMap<String, McpServerConfig>,
     #[serde(flatten)]
     pub cmd: CmdOverrides,
     #[serde(skip)]
@@ -35,6 +149,15 @@ pub struct IFlow {
 }
 
 impl IFlow {
+    /// Env var iflow is expected to read the local backend's API key from.
+    /// Set on the child's environment rather than passed as a `--api-key`
+    /// flag, since CLI args are visible to any local user via `ps`/`/proc`.
+    /// Matched against `@qwen-code/iflow`'s `--base-url`/env-driven auth
+    /// convention at the time this was written; re-check both names against
+    /// the installed CLI's `--help` output if local-backend auth stops
+    /// working after an iflow upgrade.
+    const BACKEND_API_KEY_ENV: &'static str = "IFLOW_API_KEY";
+
     fn build_command_builder(&self) -> CommandBuilder {
         let mut builder = CommandBuilder::new("npx -y @qwen-code/iflow@latest");
 
@@ -42,14 +165,425 @@ impl IFlow {
             builder = builder.extend_params(["--yolo"]);
         }
 
-        if let Some(model) = &self.model {
-            builder = builder.extend_params(["--model", model.as_str()]);
+        let model = self
+            .backend
+            .as_ref()
+            .map(|backend| backend.model.as_str())
+            .or(self.model.as_deref());
+        if let Some(model) = model {
+            builder = builder.extend_params(["--model", model]);
+        }
+
+        if let Some(backend) = &self.backend {
+            builder = builder.extend_params(["--base-url", backend.base_url.as_str()]);
         }
 
         builder = builder.extend_params(["--experimental-acp"]);
 
         apply_overrides(builder, &self.cmd)
     }
+
+    /// Resolves the configured `backend`'s API key (if any) out of
+    /// `ExecutionEnv` and returns a copy of `env` with it set on the child
+    /// process's environment under `BACKEND_API_KEY_ENV`.
+    fn resolve_env(&self, env: &ExecutionEnv) -> ExecutionEnv {
+        let api_key = self.backend.as_ref().and_then(|backend| {
+            backend
+                .api_key_env
+                .as_ref()
+                .and_then(|var_name| env.get(var_name))
+        });
+
+        match api_key {
+            Some(api_key) => env.clone().with_var(Self::BACKEND_API_KEY_ENV, api_key),
+            None => env.clone(),
+        }
+    }
+
+    /// Oldest iflow release known to support `--experimental-acp`.
+    fn min_supported_version() -> VersionReq {
+        VersionReq::parse(">=0.2.0").expect("valid version requirement")
+    }
+
+    /// Picks the first whitespace-separated token that looks like a semver
+    /// out of `iflow --version` output (e.g. `"iflow v1.2.3"` or `"1.2.3"`).
+    fn parse_version_output(stdout: &str) -> Option<Version> {
+        let raw = stdout
+            .split_whitespace()
+            .map(|token| token.trim_start_matches('v'))
+            .find(|token| token.chars().next().is_some_and(|c| c.is_ascii_digit()))?;
+
+        Version::parse(raw).ok()
+    }
+
+    /// Shells out to `iflow --version` and parses the reported semver, so
+    /// availability checks can tell an outdated install from a current one.
+    /// Polls the child with `try_wait` instead of a blocking `output()` call
+    /// so that once `VERSION_PROBE_TIMEOUT` elapses we can kill it directly
+    /// rather than just giving up on waiting for it — a cold `npx` fetch that
+    /// hangs past the timeout gets torn down instead of left running. The
+    /// `sh -c` wrapper is spawned in its own process group (`spawn_detached`)
+    /// so the timeout kill (`kill_probe_tree`) can take out the whole
+    /// `sh -> npx -> node` tree instead of just the wrapper, which `npx`
+    /// would otherwise survive under.
+    fn probe_installed_version(&self) -> Option<Version> {
+        let builder =
+            CommandBuilder::new("npx -y @qwen-code/iflow@latest").extend_params(["--version"]);
+        let builder = apply_overrides(builder, &self.cmd);
+        let probe_command = builder.build_initial().ok()?;
+        let command_str = probe_command.to_string();
+
+        let mut child = Self::spawn_detached(&command_str).ok()?;
+
+        let deadline = Instant::now() + VERSION_PROBE_TIMEOUT;
+        loop {
+            if matches!(child.try_wait(), Ok(Some(_))) {
+                break;
+            }
+            if Instant::now() >= deadline {
+                Self::kill_probe_tree(&mut child);
+                let _ = child.wait();
+                return None;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let output = child.wait_with_output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+
+        Self::parse_version_output(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// Spawns `sh -c command_str` as the leader of its own process group
+    /// (unix) or a new process group (Windows), so `kill_probe_tree` can
+    /// later reach descendants the wrapper shell spawns rather than just the
+    /// shell itself.
+    fn spawn_detached(command_str: &str) -> std::io::Result<Child> {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(command_str)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::CommandExt;
+            const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+            command.creation_flags(CREATE_NEW_PROCESS_GROUP);
+        }
+
+        command.spawn()
+    }
+
+    /// Kills the whole tree rooted at a `spawn_detached` child, not just the
+    /// `sh` wrapper, so a hung `npx`/`node` download doesn't outlive it.
+    #[cfg(unix)]
+    fn kill_probe_tree(child: &mut Child) {
+        // Negative pid addresses the process group `spawn_detached` made
+        // this child the leader of.
+        let _ = Command::new("kill")
+            .arg("-TERM")
+            .arg(format!("-{}", child.id()))
+            .status();
+        let _ = child.kill();
+    }
+
+    #[cfg(windows)]
+    fn kill_probe_tree(child: &mut Child) {
+        let _ = Command::new("taskkill")
+            .args(["/PID", &child.id().to_string(), "/T", "/F"])
+            .status();
+        let _ = child.kill();
+    }
+
+    /// Resolves a `base_url` down to a `host:port` string, defaulting the
+    /// port from the scheme (`https` -> 443, anything else -> 80) when the
+    /// URL doesn't specify one explicitly.
+    fn resolve_host_port(base_url: &str) -> String {
+        let (scheme, rest) = base_url.split_once("://").unwrap_or(("http", base_url));
+        let host_port = rest.split('/').next().unwrap_or(rest);
+
+        if host_port.contains(':') {
+            host_port.to_string()
+        } else {
+            let default_port = if scheme.eq_ignore_ascii_case("https") {
+                443
+            } else {
+                80
+            };
+            format!("{host_port}:{default_port}")
+        }
+    }
+
+    /// Best-effort TCP reachability check for a locally-hosted backend, so we
+    /// don't report `InstallationFound` for an endpoint nobody is listening on.
+    fn backend_reachable(base_url: &str) -> bool {
+        Self::resolve_host_port(base_url)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .is_some_and(|addr| {
+                TcpStream::connect_timeout(&addr, Duration::from_millis(300)).is_ok()
+            })
+    }
+
+    /// Renders `mcp_servers` into an iflow-shaped settings file under the
+    /// system temp dir and returns its path, so each task can give the agent
+    /// a different set of MCP tools without editing the global settings.json.
+    ///
+    /// The path is derived from a hash of the rendered config rather than a
+    /// fresh UUID, so repeated calls for the same task (`default_mcp_config_path`
+    /// is invoked on every spawn) overwrite the same file instead of leaking a
+    /// new one each time. Servers are sorted and `env`/`args` serialize through
+    /// `serde_json::Map`'s default `BTreeMap` backing, so the rendered bytes
+    /// — and therefore the path — are stable across calls for the same config.
+    fn generate_mcp_config_path(&self) -> Option<PathBuf> {
+        if self.mcp_servers.is_empty() {
+            return None;
+        }
+
+        let mut entries: Vec<(&String, &McpServerConfig)> = self.mcp_servers.iter().collect();
+        entries.sort_by_key(|(name, _)| name.as_str());
+
+        let servers: serde_json::Map<String, serde_json::Value> = entries
+            .into_iter()
+            .filter(|(_, server)| server.enabled)
+            .map(|(name, server)| {
+                (
+                    name.clone(),
+                    serde_json::json!({
+                        "command": server.command,
+                        "args": server.args,
+                        "env": server.env,
+                    }),
+                )
+            })
+            .collect();
+
+        let config = serde_json::json!({ "mcpServers": servers });
+        let bytes = serde_json::to_vec_pretty(&config).ok()?;
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        let path = std::env::temp_dir().join(format!("iflow-mcp-{:016x}.json", hasher.finish()));
+        std::fs::write(&path, &bytes).ok()?;
+        Self::restrict_to_owner(&path);
+
+        Some(path)
+    }
+
+    /// Server `env` entries can carry API keys/tokens, so the generated file
+    /// shouldn't be left at the system temp dir's default (umask-controlled,
+    /// typically world-readable) permissions. Best-effort: a failure here
+    /// still leaves the file written, just not locked down.
+    #[cfg(unix)]
+    fn restrict_to_owner(path: &Path) {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600));
+    }
+
+    #[cfg(not(unix))]
+    fn restrict_to_owner(_path: &Path) {}
+
+    /// Surfaces the version/min-support detail through this iflow-specific
+    /// accessor rather than a richer `AvailabilityInfo` variant.
+    /// `probe_availability` below calls this so the probe actually runs as
+    /// part of every availability check instead of sitting unused behind the
+    /// unit tests.
+    ///
+    /// Cached under the same `AVAILABILITY_CACHE_TTL` as the rest of
+    /// availability checking, since this shells out to `npx` and shouldn't
+    /// reprobe on every call.
+    pub fn installed_version_info(&self) -> IFlowVersionInfo {
+        if let Some((checked_at, info)) = version_cache().lock().unwrap().as_ref() {
+            if checked_at.elapsed() < AVAILABILITY_CACHE_TTL {
+                return info.clone();
+            }
+        }
+
+        let version = self.probe_installed_version();
+        let satisfies_min = version
+            .as_ref()
+            .is_some_and(|v| Self::min_supported_version().matches(v));
+
+        if version.is_some() && !satisfies_min {
+            tracing::warn!(
+                "detected iflow install is older than the minimum supported version; \
+                 --experimental-acp may not be available"
+            );
+        }
+
+        let info = IFlowVersionInfo {
+            version: version.map(|v| v.to_string()),
+            satisfies_min,
+        };
+
+        *version_cache().lock().unwrap() = Some((Instant::now(), info.clone()));
+        info
+    }
+
+    /// Availability depends on more than just "is iflow installed" — e.g. a
+    /// configured `backend` must also be reachable — so the cache is keyed
+    /// on whatever actually affects the result, not a single global slot.
+    fn availability_cache_key(&self) -> String {
+        self.backend
+            .as_ref()
+            .map(|backend| format!("backend={}", backend.base_url))
+            .unwrap_or_default()
+    }
+
+    fn probe_availability(&self) -> AvailabilityInfo {
+        let mcp_config_found = dirs::home_dir()
+            .map(|home| home.join(".iflow").join("settings.json").exists())
+            .unwrap_or(false);
+
+        let installation_indicator_found = dirs::home_dir()
+            .map(|home| home.join(".iflow").join("installation_id").exists())
+            .unwrap_or(false);
+
+        if !(mcp_config_found || installation_indicator_found) {
+            return AvailabilityInfo::NotFound;
+        }
+
+        if let Some(backend) = &self.backend {
+            if !Self::backend_reachable(&backend.base_url) {
+                return AvailabilityInfo::NotFound;
+            }
+        }
+
+        // Run the version probe (cached, see `version_cache`) as part of
+        // every availability check, instead of leaving it reachable only via
+        // the public `installed_version_info` accessor. `AvailabilityInfo`
+        // can't carry `satisfies_min`/`version` itself here, but the
+        // `tracing::warn!` in `installed_version_info` still fires for an
+        // old install.
+        let _ = self.installed_version_info();
+
+        AvailabilityInfo::InstallationFound
+    }
+
+    /// Checks the process we recorded against `session_id` in
+    /// `spawn`/`spawn_follow_up` is both alive and still the same process —
+    /// not just a pid that exists again after being reassigned to something
+    /// unrelated. Reading real OS state instead of a flag we'd have to
+    /// remember to flip means this self-corrects once the agent exits on its
+    /// own: a completed task stops reporting "running" without anything
+    /// having to notice the exit and clear it, and a stale entry is pruned
+    /// from `session_registry` the first time it's found to be stale.
+    pub fn is_session_running(&self, session_id: &str) -> bool {
+        let entry = session_registry().lock().unwrap().get(session_id).cloned();
+        let Some((pid, recorded_marker)) = entry else {
+            return false;
+        };
+
+        let same_process = match (&recorded_marker, Self::process_start_marker(pid)) {
+            (Some(recorded), Some(current)) => *recorded == current,
+            // No start-time marker available on this platform (or the
+            // process is already gone): fall back to plain existence, which
+            // can't rule out pid reuse but is the best this platform offers.
+            _ => Self::pid_alive(pid),
+        };
+
+        if !same_process {
+            session_registry().lock().unwrap().remove(session_id);
+        }
+        same_process
+    }
+
+    /// An opaque, OS-specific string that changes if `pid` is reused by a
+    /// different process, so liveness checks can detect reuse instead of
+    /// trusting pid existence alone. `None` if the process is gone or no
+    /// marker is available on this platform.
+    #[cfg(target_os = "linux")]
+    fn process_start_marker(pid: u32) -> Option<String> {
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+        // The `comm` field is wrapped in the last `(...)` and can itself
+        // contain whitespace, so skip past it before splitting on whitespace.
+        let after_comm = stat.rsplit_once(')')?.1;
+        // `starttime` is the 22nd field overall, i.e. index 19 once `pid` and
+        // `(comm)` (fields 1-2) are stripped off.
+        after_comm
+            .split_whitespace()
+            .nth(19)
+            .map(|starttime| starttime.to_string())
+    }
+
+    #[cfg(all(unix, not(target_os = "linux")))]
+    fn process_start_marker(pid: u32) -> Option<String> {
+        let output = Command::new("ps")
+            .args(["-o", "lstart=", "-p", &pid.to_string()])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let marker = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        (!marker.is_empty()).then_some(marker)
+    }
+
+    #[cfg(windows)]
+    fn process_start_marker(_pid: u32) -> Option<String> {
+        // No lightweight way to read a process's start time on Windows
+        // without an extra dependency; liveness falls back to plain
+        // existence there (see `is_session_running`), which can't rule out
+        // pid reuse.
+        None
+    }
+
+    /// Whether `pid` still refers to a live process.
+    #[cfg(unix)]
+    fn pid_alive(pid: u32) -> bool {
+        Command::new("kill")
+            .arg("-0")
+            .arg(pid.to_string())
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    #[cfg(windows)]
+    fn pid_alive(pid: u32) -> bool {
+        Command::new("tasklist")
+            .args(["/FI", &format!("PID eq {pid}"), "/NH"])
+            .output()
+            .is_ok_and(|output| {
+                String::from_utf8_lossy(&output.stdout).contains(&pid.to_string())
+            })
+    }
+
+    /// Terminates the process recorded against `session_id`, so a runaway
+    /// follow-up can actually be stopped rather than just forgotten locally.
+    /// Shells out rather than depending on a process-signalling crate not
+    /// already used elsewhere in this file.
+    pub fn cancel_session(&self, session_id: &str) -> Result<(), ExecutorError> {
+        let entry = session_registry().lock().unwrap().remove(session_id);
+        if let Some((pid, _)) = entry {
+            Self::kill_pid(pid);
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn kill_pid(pid: u32) {
+        let _ = Command::new("kill")
+            .arg("-TERM")
+            .arg(pid.to_string())
+            .status();
+    }
+
+    #[cfg(windows)]
+    fn kill_pid(pid: u32) {
+        let _ = Command::new("taskkill")
+            .args(["/PID", &pid.to_string(), "/F"])
+            .status();
+    }
 }
 
 #[async_trait]
@@ -64,24 +598,36 @@ impl StandardCodingAgentExecutor for IFlow {
         prompt: &str,
         env: &ExecutionEnv,
     ) -> Result<SpawnedChild, ExecutorError> {
-        let harness = AcpAgentHarness::new();
+        let harness = shared_harness();
         let combined_prompt = self.append_prompt.combine_prompt(prompt);
         let iflow_command = self.build_command_builder().build_initial()?;
+        let resolved_env = self.resolve_env(env);
         let approvals = if self.yolo.unwrap_or(false) {
             None
         } else {
             self.approvals.clone()
         };
-        harness
+        let spawned = harness
             .spawn_with_command(
                 current_dir,
                 combined_prompt,
                 iflow_command,
-                env,
+                &resolved_env,
                 &self.cmd,
                 approvals,
             )
-            .await
+            .await?;
+
+        // `SpawnedChild` is where the ACP session id first becomes known (a
+        // caller has to learn it from somewhere to pass into
+        // `spawn_follow_up`/`is_session_running` later), so this is the only
+        // place an initial spawn can register itself.
+        session_registry().lock().unwrap().insert(
+            spawned.session_id.clone(),
+            (spawned.pid, Self::process_start_marker(spawned.pid)),
+        );
+
+        Ok(spawned)
     }
 
     async fn spawn_follow_up(
@@ -91,49 +637,236 @@ impl StandardCodingAgentExecutor for IFlow {
         session_id: &str,
         env: &ExecutionEnv,
     ) -> Result<SpawnedChild, ExecutorError> {
-        let harness = AcpAgentHarness::new();
+        let harness = shared_harness();
         let combined_prompt = self.append_prompt.combine_prompt(prompt);
         let iflow_command = self.build_command_builder().build_follow_up(&[])?;
+        let resolved_env = self.resolve_env(env);
         let approvals = if self.yolo.unwrap_or(false) {
             None
         } else {
             self.approvals.clone()
         };
-        harness
+        let spawned = harness
             .spawn_follow_up_with_command(
                 current_dir,
                 combined_prompt,
                 session_id,
                 iflow_command,
-                env,
+                &resolved_env,
                 &self.cmd,
                 approvals,
             )
-            .await
+            .await?;
+
+        session_registry().lock().unwrap().insert(
+            session_id.to_string(),
+            (spawned.pid, Self::process_start_marker(spawned.pid)),
+        );
+
+        Ok(spawned)
     }
 
     fn normalize_logs(&self, msg_store: Arc<MsgStore>, worktree_path: &Path) {
         crate::executors::acp::normalize_logs(msg_store, worktree_path);
     }
 
-    fn default_mcp_config_path(&self) -> Option<std::path::PathBuf> {
-        dirs::home_dir().map(|home| home.join(".iflow").join("settings.json"))
+    fn default_mcp_config_path(&self) -> Option<PathBuf> {
+        self.generate_mcp_config_path()
+            .or_else(|| dirs::home_dir().map(|home| home.join(".iflow").join("settings.json")))
     }
 
     fn get_availability_info(&self) -> AvailabilityInfo {
-        let mcp_config_found = self
-            .default_mcp_config_path()
-            .map(|p| p.exists())
-            .unwrap_or(false);
+        let key = self.availability_cache_key();
+        let cache = availability_cache();
+        let mut guard = cache.lock().unwrap();
 
-        let installation_indicator_found = dirs::home_dir()
-            .map(|home| home.join(".iflow").join("installation_id").exists())
-            .unwrap_or(false);
+        if let Some((checked_at, info)) = guard.get(&key) {
+            if checked_at.elapsed() < AVAILABILITY_CACHE_TTL {
+                return info.clone();
+            }
+        }
 
-        if mcp_config_found || installation_indicator_found {
-            AvailabilityInfo::InstallationFound
-        } else {
-            AvailabilityInfo::NotFound
+        let info = self.probe_availability();
+        guard.insert(key, (Instant::now(), info.clone()));
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_version_output_handles_v_prefix() {
+        let version = IFlow::parse_version_output("iflow v1.2.3\n").unwrap();
+        assert_eq!(version.to_string(), "1.2.3");
+    }
+
+    #[test]
+    fn parse_version_output_handles_bare_semver() {
+        let version = IFlow::parse_version_output("1.2.3").unwrap();
+        assert_eq!(version.to_string(), "1.2.3");
+    }
+
+    #[test]
+    fn parse_version_output_returns_none_without_a_version_token() {
+        assert!(IFlow::parse_version_output("command not found").is_none());
+    }
+
+    #[test]
+    fn resolve_host_port_defaults_http_to_port_80() {
+        assert_eq!(IFlow::resolve_host_port("http://localhost"), "localhost:80");
+    }
+
+    #[test]
+    fn resolve_host_port_defaults_https_to_port_443() {
+        assert_eq!(
+            IFlow::resolve_host_port("https://model.local"),
+            "model.local:443"
+        );
+    }
+
+    #[test]
+    fn resolve_host_port_keeps_an_explicit_port() {
+        assert_eq!(
+            IFlow::resolve_host_port("http://localhost:8080/v1"),
+            "localhost:8080"
+        );
+    }
+
+    fn test_iflow(backend: Option<LocalModelBackend>, mcp_servers: HashMap<String, McpServerConfig>) -> IFlow {
+        IFlow {
+            append_prompt: AppendPrompt::default(),
+            yolo: None,
+            model: None,
+            backend,
+            mcp_servers,
+            cmd: CmdOverrides::default(),
+            approvals: None,
+        }
+    }
+
+    #[test]
+    fn resolve_env_passes_the_backend_api_key_through_under_its_own_name() {
+        let backend = LocalModelBackend {
+            base_url: "http://localhost:1234".to_string(),
+            api_key_env: Some("MY_LOCAL_KEY".to_string()),
+            model: "local-model".to_string(),
+        };
+        let iflow = test_iflow(Some(backend), HashMap::new());
+        let env = ExecutionEnv::default().with_var("MY_LOCAL_KEY", "super-secret");
+
+        let resolved = iflow.resolve_env(&env);
+
+        assert_eq!(
+            resolved.get(IFlow::BACKEND_API_KEY_ENV),
+            Some("super-secret".to_string())
+        );
+    }
+
+    #[test]
+    fn resolve_env_is_a_no_op_without_a_configured_backend() {
+        let iflow = test_iflow(None, HashMap::new());
+        let env = ExecutionEnv::default();
+
+        let resolved = iflow.resolve_env(&env);
+
+        assert_eq!(resolved.get(IFlow::BACKEND_API_KEY_ENV), None);
+    }
+
+    #[test]
+    fn resolve_env_is_a_no_op_when_the_configured_var_is_unset() {
+        let backend = LocalModelBackend {
+            base_url: "http://localhost:1234".to_string(),
+            api_key_env: Some("MY_LOCAL_KEY".to_string()),
+            model: "local-model".to_string(),
+        };
+        let iflow = test_iflow(Some(backend), HashMap::new());
+        let env = ExecutionEnv::default();
+
+        let resolved = iflow.resolve_env(&env);
+
+        assert_eq!(resolved.get(IFlow::BACKEND_API_KEY_ENV), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn generate_mcp_config_path_is_not_world_readable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut servers = HashMap::new();
+        servers.insert(
+            "secrets".to_string(),
+            McpServerConfig {
+                enabled: true,
+                command: "secret-tool".to_string(),
+                args: vec![],
+                env: HashMap::from([("TOKEN".to_string(), "super-secret".to_string())]),
+            },
+        );
+        let iflow = test_iflow(None, servers);
+
+        let path = iflow.generate_mcp_config_path().unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn is_session_running_is_false_for_an_unknown_session() {
+        let iflow = test_iflow(None, HashMap::new());
+        assert!(!iflow.is_session_running("no-such-session"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn is_session_running_prunes_a_stale_entry_for_a_dead_pid() {
+        let iflow = test_iflow(None, HashMap::new());
+
+        // Spawn and immediately reap a child so its pid is guaranteed dead
+        // (modulo the OS recycling it before the assertions below run).
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+
+        session_registry()
+            .lock()
+            .unwrap()
+            .insert("stale".to_string(), (pid, IFlow::process_start_marker(pid)));
+
+        assert!(!iflow.is_session_running("stale"));
+        assert!(!session_registry().lock().unwrap().contains_key("stale"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cancel_session_terminates_the_process_and_clears_the_registry() {
+        let iflow = test_iflow(None, HashMap::new());
+
+        let mut child = Command::new("sleep").arg("5").spawn().unwrap();
+        let pid = child.id();
+        session_registry().lock().unwrap().insert(
+            "to-cancel".to_string(),
+            (pid, IFlow::process_start_marker(pid)),
+        );
+
+        assert!(iflow.is_session_running("to-cancel"));
+
+        iflow.cancel_session("to-cancel").unwrap();
+        assert!(!session_registry().lock().unwrap().contains_key("to-cancel"));
+
+        // Reap the child as soon as it exits: an unreaped zombie still
+        // answers `kill -0`, so `pid_alive` can only report it gone after this.
+        let mut exit_status = None;
+        for _ in 0..20 {
+            if let Some(status) = child.try_wait().unwrap() {
+                exit_status = Some(status);
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
         }
+        assert!(exit_status.is_some_and(|status| !status.success()));
+        assert!(!IFlow::pid_alive(pid));
     }
 }
\ No newline at end of file